@@ -1,27 +1,56 @@
-use std::{mem, fmt};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::ops::{Index, IndexMut};
-use std::marker::PhantomData;
-use std::collections::LinkedList;
-use std::ops::{Deref, DerefMut};
-use std::ptr;
-
-
+#![cfg_attr(not(feature = "std"), no_std)]
+
+use core::{mem, fmt};
+use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use core::ops::{Index, IndexMut};
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+#[cfg(feature = "std")]
+use core::future::Future;
+#[cfg(feature = "std")]
+use core::pin::Pin;
+#[cfg(feature = "std")]
+use core::task::{Context, Poll, Waker};
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+
+
+#[cfg(feature = "std")]
 pub mod tests;
 
 /// Arc is the only valid way to access an item in
 /// the pool. It is returned by alloc, and will automatically
-/// release/retain when dropped/cloned. It implements Deref/DerefMut,
-/// so all accesses can go through it.
+/// release/retain when dropped/cloned. It implements Deref for shared
+/// access; mutable access goes through `get_mut`, which (like
+/// `std::sync::Arc::get_mut`) only succeeds while this is the sole
+/// handle to the slot.
 /// WARNING! Taking the address of the dereferenced value constitutes
 /// undefined behavior. So, given a: Arc<T>, &*a is not allowed
-pub struct Arc<T> {
-    pool: *mut Pool<T>,
+pub struct Arc<T, H: SlotMeta = DefaultHeader> {
+    pool: *mut Pool<T, H>,
     index: usize,
+    generation: usize,
 }
 
+// SAFETY: `Arc` is a shared-ownership handle into a `Pool` -- cloning it
+// and using the clones from different threads is exactly the scenario
+// `Pool`'s own `Sync` impl is already sound for, so the same bounds
+// apply here. The raw `*mut Pool` is never dereferenced mutably (see
+// get_pool), only used to reach `Pool`'s `&self` methods. Exclusive
+// access to the payload (get_mut) is gated on ref_count == 1 and
+// pin_count == 0, the same header state a clone or a `Ref` guard on
+// another thread would be observing, so neither can ever produce a
+// `&T` or `&mut T` that overlaps a `get_mut` across threads either.
+unsafe impl <T: Send + Sync, H: SlotMeta + Send + Sync> Send for Arc<T, H> {}
+unsafe impl <T: Send + Sync, H: SlotMeta + Send + Sync> Sync for Arc<T, H> {}
+
 /// Public functions
-impl <T> Arc<T> {
+impl <T, H: SlotMeta> Arc<T, H> {
     /// If you want to manually manage the memory or
     /// use the wrapped reference outside of the Arc system
     /// the retain/release functions provide an escape hatch.
@@ -37,50 +66,78 @@ impl <T> Arc<T> {
     pub unsafe fn release(&mut self) {
         self.get_pool().release(self.index);
     }
+
+    /// Like Deref, but returns None if the slot has since been recycled
+    /// for another object (i.e. this Arc's generation is stale); Deref
+    /// itself does not check the generation. Useful for callers that hold an index/generation pair
+    /// across a point where the slot might have been freed and reused.
+    pub fn try_deref(&self) -> Option<&T> {
+        self.get_pool().try_get(self.index, self.generation)
+    }
+
+    /// Mutable access to the payload, but only while this is the sole
+    /// handle to the slot: returns `None` if another clone is alive
+    /// (`ref_count != 1`) or a `Ref` guard from `Pool::get` is outstanding
+    /// (`pin_count != 0`). Mirrors `std::sync::Arc::get_mut`'s exclusivity
+    /// gate, extended to cover `Ref` since it's just as much a live read
+    /// handle to the slot as another `Arc` clone; `Arc` has no
+    /// unconditional `DerefMut` for the same reason `std::sync::Arc`
+    /// doesn't.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        let h = self.get_pool().header_for(self.index);
+        if h.ref_count() == 1 && h.pin_count() == 0 {
+            Some(unsafe { mem::transmute(self.get_pool().raw_contents_for(self.index)) })
+        } else {
+            None
+        }
+    }
 }
 
 /// Internal functions
-impl <T> Arc<T> {
+impl <T, H: SlotMeta> Arc<T, H> {
 
     /// It's somewhat confusing that Arc::new()
     /// does not take care of bumping the ref count.
     /// However, the atomic op for claiming a free slot
     /// needs to happen before the new() takes place
-    fn new(index: usize, p: &Pool<T>) -> Arc<T> {
+    fn new(index: usize, p: &Pool<T, H>) -> Arc<T, H> {
+        let generation = p.header_for(index).generation();
         Arc {
             pool: unsafe { mem::transmute(p) },
             index: index,
+            generation: generation,
         }
     }
 
-    fn get_pool(&self) -> &mut Pool<T> {
+    fn get_pool(&self) -> &Pool<T, H> {
         unsafe {
-            &mut *self.pool
+            &*self.pool
         }
     }
 
     fn ref_count(&self) -> usize {
-        self.get_pool().header_for(self.index).ref_count.load(Ordering::Relaxed)
+        self.get_pool().header_for(self.index).ref_count()
     }
 }
 
-impl <T> Drop for Arc<T> {
+impl <T, H: SlotMeta> Drop for Arc<T, H> {
     fn drop(&mut self) {
         self.get_pool().release(self.index);
     }
 }
 
-impl <T> Clone for Arc<T> {
+impl <T, H: SlotMeta> Clone for Arc<T, H> {
     fn clone(&self) -> Self {
         self.get_pool().retain(self.index);
         Arc {
             pool: self.pool,
             index: self.index,
+            generation: self.generation,
         }
     }
 }
 
-impl<T> Deref for Arc<T> {
+impl<T, H: SlotMeta> Deref for Arc<T, H> {
     type Target = T;
 
     fn deref<'b>(&'b self) -> &'b T {
@@ -88,20 +145,14 @@ impl<T> Deref for Arc<T> {
     }
 }
 
-impl<T> DerefMut for Arc<T> {
-    fn deref_mut<'b>(&'b mut self) -> &'b mut T {
-        &mut self.get_pool()[self.index]
-    }
-}
-
-impl <T> fmt::Debug for Arc<T> {
+impl <T, H: SlotMeta> fmt::Debug for Arc<T, H> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Arc{{ offset: {:?}, ref_count: {:?} }}", self.index, self.ref_count())
+        write!(f, "Arc{{ offset: {:?}, generation: {:?}, ref_count: {:?} }}", self.index, self.generation, self.ref_count())
     }
 }
 
-impl <T> PartialEq for Arc<T> {
-    fn eq(&self, other: &Arc<T>) -> bool {
+impl <T, H: SlotMeta> PartialEq for Arc<T, H> {
+    fn eq(&self, other: &Arc<T, H>) -> bool {
         if self.index != other.index {
             false
         } else {
@@ -112,15 +163,72 @@ impl <T> PartialEq for Arc<T> {
     }
 }
 
+const USIZE_BITS: u32 = mem::size_of::<usize>() as u32 * 8;
+
+// Number of high bits of `Pool::free_head` to reserve for the index (the
+// tag takes the low bits, see pack_free_head), given a pool of
+// `capacity` slots: just enough to address every slot plus one
+// spare value for the "empty" sentinel. Everything else goes to the
+// ABA-defeating tag, so small pools (the common case) get a much wider
+// tag than a pool sized near usize::max_value() would.
+fn free_list_index_bits(capacity: usize) -> u32 {
+    (USIZE_BITS - capacity.leading_zeros()).max(1)
+}
+
+fn free_list_tag_bits(capacity: usize) -> u32 {
+    USIZE_BITS - free_list_index_bits(capacity)
+}
+
+// Sentinel index meaning "the free stack is empty", for a pool whose
+// free-list tag is `tag_bits` wide. Pools never fill every index bit
+// (see free_list_index_bits), so this is always distinct from every
+// valid slot index.
+fn free_list_empty(tag_bits: u32) -> usize {
+    usize::max_value() >> tag_bits
+}
+
+fn pack_free_head(index: usize, tag: usize, tag_bits: u32) -> usize {
+    (index << tag_bits) | (tag & ((1 << tag_bits) - 1))
+}
+
+fn unpack_free_head(packed: usize, tag_bits: u32) -> (usize, usize) {
+    (packed >> tag_bits, packed & ((1 << tag_bits) - 1))
+}
+
+// Rounds `value` up to the nearest multiple of `align`.
+fn round_up(value: usize, align: usize) -> usize {
+    let rem = value % align;
+    if rem == 0 { value } else { value + (align - rem) }
+}
+
+// Rounds `value` down to the nearest multiple of `align`.
+fn round_down(value: usize, align: usize) -> usize {
+    value - (value % align)
+}
+
 /// A pool represents a fixed number of ref-counted objects.
 /// The pool treats all given space as an unallocated
-/// pool of objects. Each object is prefixed with a header.
-/// The header is formatted as follows:
-/// * V1
-///   - [0..2] ref_count: u16
+/// pool of objects. Each object is prefixed with a header, whose type
+/// is pluggable via the `H: SlotMeta` parameter: `DefaultHeader` (the
+/// default) and `U16Header` are provided, and custom headers can embed
+/// extra per-slot metadata alongside the ref count/generation.
+///
+/// Freed slots are threaded into a lock-free (Treiber) stack: `free_head`
+/// holds the index of the top slot tagged with a monotonic counter to
+/// defeat ABA, and the "next free" link is stored inside the freed slot's
+/// own payload rather than in a side collection. Because of this, a
+/// slot's payload region must be at least `size_of::<usize>()` bytes, so
+/// small `T`s are padded up to that size.
 ///
-pub struct Pool<T> {
+/// Slots are laid out so that every payload pointer handed out is
+/// correctly aligned for `T`: the buffer's base is advanced to the first
+/// address satisfying `max(H::header_align(), align_of::<T>())`,
+/// the payload sits at the first such-aligned offset after the header,
+/// and `slot_size` itself is rounded up to a multiple of that alignment
+/// so the stride keeps every subsequent slot aligned too.
+pub struct Pool<T, H: SlotMeta = DefaultHeader> {
     item_type: PhantomData<T>,
+    header_type: PhantomData<H>,
 
     buffer: *mut u8,
     buffer_size: usize,
@@ -131,29 +239,312 @@ pub struct Pool<T> {
     // Cached values
     slot_size: usize,
     header_size: usize,
+    payload_offset: usize, // Offset of the payload within a slot, >= header_size
+    free_list_tag_bits: u32, // Width of free_head's ABA tag, see free_list_tag_bits()
+
+    free_head: AtomicUsize, // Top of the intrusive free stack, see pack_free_head
+    free_count: AtomicUsize, // Number of slots currently on the free stack
+
+    // Wakers registered by alloc_async() callers parked on an empty pool,
+    // each tagged with the token register_waiter() handed back so a
+    // caller whose recheck finds a slot after all can cancel_waiter() its
+    // own registration instead of leaving it to be woken later for
+    // nothing. Woken one at a time as release() frees a slot. Requires an
+    // allocator, so it's only available with the "std" feature.
+    #[cfg(feature = "std")]
+    waiters: Mutex<VecDeque<(usize, Waker)>>,
+    #[cfg(feature = "std")]
+    next_waiter_token: AtomicUsize,
+}
+
+// SAFETY: `buffer`/`buffer_size`/`capacity`/`slot_size`/`header_size`/
+// `payload_offset` are set once in `new` and never mutated again; every
+// other field is either an atomic or (with "std") a `Mutex`, and every
+// access to a slot's `T` or `H` goes through one of those (retain/
+// release/try_get/get/indexing, all `&self`). So sharing `&Pool` across
+// threads is sound as long as the `T`s and `H`s actually living in its
+// slots are themselves `Sync` (and `Send`, since a `Ref`/shared read on
+// one thread can observe a `T` written by another).
+unsafe impl <T: Send + Sync, H: SlotMeta + Send + Sync> Sync for Pool<T, H> {}
+
+// SAFETY: moving a `Pool` to another thread moves ownership of every
+// slot's `T` and `H` along with it, the same requirement any other
+// container placing them behind a pointer would have.
+unsafe impl <T: Send, H: SlotMeta + Send> Send for Pool<T, H> {}
+
+/// A pluggable per-slot header: controls how a slot's reference count
+/// and use-after-free generation tag are stored (and how large that
+/// bookkeeping is), and gives custom headers a hook to stamp extra
+/// per-slot metadata (flags, type tags, timestamps) on allocation.
+/// `Pool<T>` defaults to `DefaultHeader`; see also `U16Header`.
+///
+/// Implementations must use `Acquire`/`Release` (or `AcqRel`) on
+/// `ref_count`, `generation` and `pin_count`, not `Relaxed`: a caller
+/// validating a previously-captured `(index, generation)` pair via
+/// `try_get`/`get` on one thread has to be synchronized with whichever
+/// thread last called `bump_generation`/`retain`/`pin` on another,
+/// or it can observe a stale generation and race a concurrent reuse of
+/// the slot -- exactly the hazard these counters exist to catch, and
+/// the reason `Pool` targets weakly-ordered platforms in the first
+/// place.
+pub trait SlotMeta: Default {
+    /// Byte size of the header. The payload for each slot begins here,
+    /// rounded up to the payload's own alignment.
+    fn header_size() -> usize {
+        mem::size_of::<Self>()
+    }
+
+    /// Required alignment of the header.
+    fn header_align() -> usize {
+        mem::align_of::<Self>()
+    }
+
+    /// Called once a slot has been claimed (from the free stack, or
+    /// bump-allocated from the tail), before the `Arc` handed back to the
+    /// caller is built. The default implementation clears the reclaim
+    /// latch (see `try_reclaim`) and takes the first reference; headers
+    /// that stamp additional metadata on allocation should call
+    /// `retain`/`clear_reclaim` themselves and do so here.
+    fn on_alloc(&self) {
+        self.clear_reclaim();
+        self.retain();
+    }
+
+    /// Bumps the ref count, returning the new count.
+    fn retain(&self) -> usize;
+
+    /// Decrements the ref count, returning the new count. Panics if the
+    /// count was already zero.
+    fn release(&self) -> usize;
+
+    fn ref_count(&self) -> usize;
 
-    free_list: LinkedList<usize>,
+    fn generation(&self) -> usize;
+
+    /// Bumps the generation tag, returning the new value. Called when
+    /// the last reference to a slot is dropped, before it goes back on
+    /// the free stack, so a stale `Arc`/index pair can never observe the
+    /// old generation again.
+    fn bump_generation(&self) -> usize;
+
+    /// Bumps the count of outstanding `Ref` guards borrowing this slot,
+    /// returning the new count. Called by `Pool::get`.
+    fn pin(&self) -> usize;
+
+    /// Decrements the outstanding-`Ref` count, returning the new count.
+    /// Panics if it was already zero. Called when a `Ref` drops.
+    fn unpin(&self) -> usize;
+
+    fn pin_count(&self) -> usize;
+
+    /// Attempts to claim this slot for reclamation, succeeding (and
+    /// returning `true`) exactly once per free/realloc cycle. `release`
+    /// and the last outstanding `Ref::drop` both call this after their
+    /// own count reaches zero, each first checking that the *other*
+    /// count is also zero; the compare-and-swap latch here is what
+    /// guarantees that if both race to be "the last one out" at once,
+    /// only one of them actually bumps the generation and pushes the
+    /// slot onto the free stack.
+    fn try_reclaim(&self) -> bool;
+
+    /// Resets the reclaim latch so the slot can be freed again the next
+    /// time its ref count and pin count both reach zero. Called by the
+    /// default `on_alloc` when a slot is claimed.
+    fn clear_reclaim(&self);
 }
 
-struct SlotHeader {
+/// The original per-slot header: a pair of `AtomicUsize`s for the ref
+/// count and the generation tag, plus the bookkeeping `Ref` guards need
+/// (an outstanding-borrow count and a reclaim latch). Simple, and gives
+/// the ref count and generation their full native range.
+#[derive(Default)]
+pub struct DefaultHeader {
     ref_count: AtomicUsize,
+    generation: AtomicUsize,
+    pin_count: AtomicUsize,
+    reclaiming: AtomicBool,
+}
+
+impl SlotMeta for DefaultHeader {
+    fn retain(&self) -> usize {
+        loop {
+            let old = self.ref_count.load(Ordering::Acquire);
+            let swap = self.ref_count.compare_and_swap(old, old + 1, Ordering::AcqRel);
+            if swap == old {
+                break old + 1
+            }
+        }
+    }
+
+    fn release(&self) -> usize {
+        loop {
+            let old = self.ref_count.load(Ordering::Acquire);
+            assert!(old > 0, "release called on a slot with no refs!");
+            let swap = self.ref_count.compare_and_swap(old, old - 1, Ordering::AcqRel);
+            if swap == old {
+                break old - 1
+            }
+        }
+    }
+
+    fn ref_count(&self) -> usize {
+        self.ref_count.load(Ordering::Acquire)
+    }
+
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn bump_generation(&self) -> usize {
+        self.generation.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    fn pin(&self) -> usize {
+        self.pin_count.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    fn unpin(&self) -> usize {
+        let old = self.pin_count.fetch_sub(1, Ordering::AcqRel);
+        assert!(old > 0, "unpin called on a slot with no outstanding Refs!");
+        old - 1
+    }
+
+    fn pin_count(&self) -> usize {
+        self.pin_count.load(Ordering::Acquire)
+    }
+
+    fn try_reclaim(&self) -> bool {
+        !self.reclaiming.compare_and_swap(false, true, Ordering::AcqRel)
+    }
+
+    fn clear_reclaim(&self) {
+        self.reclaiming.store(false, Ordering::Relaxed);
+    }
+}
+
+/// A compact header for dense pools: packs the ref count, generation and
+/// pin count into `AtomicU16`s, at the cost of wrapping after 65535
+/// outstanding refs/borrows or recycles of a single slot. Fine whenever a
+/// pool's slot count, rather than any one slot's counters, is what
+/// dominates.
+#[derive(Default)]
+pub struct U16Header {
+    ref_count: core::sync::atomic::AtomicU16,
+    generation: core::sync::atomic::AtomicU16,
+    pin_count: core::sync::atomic::AtomicU16,
+    reclaiming: AtomicBool,
+}
+
+impl SlotMeta for U16Header {
+    fn retain(&self) -> usize {
+        loop {
+            let old = self.ref_count.load(Ordering::Acquire);
+            let swap = self.ref_count.compare_and_swap(old, old.wrapping_add(1), Ordering::AcqRel);
+            if swap == old {
+                break old.wrapping_add(1) as usize
+            }
+        }
+    }
+
+    fn release(&self) -> usize {
+        loop {
+            let old = self.ref_count.load(Ordering::Acquire);
+            assert!(old > 0, "release called on a slot with no refs!");
+            let swap = self.ref_count.compare_and_swap(old, old - 1, Ordering::AcqRel);
+            if swap == old {
+                break (old - 1) as usize
+            }
+        }
+    }
+
+    fn ref_count(&self) -> usize {
+        self.ref_count.load(Ordering::Acquire) as usize
+    }
+
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire) as usize
+    }
+
+    fn bump_generation(&self) -> usize {
+        self.generation.fetch_add(1, Ordering::AcqRel).wrapping_add(1) as usize
+    }
+
+    fn pin(&self) -> usize {
+        self.pin_count.fetch_add(1, Ordering::AcqRel).wrapping_add(1) as usize
+    }
+
+    fn unpin(&self) -> usize {
+        let old = self.pin_count.fetch_sub(1, Ordering::AcqRel);
+        assert!(old > 0, "unpin called on a slot with no outstanding Refs!");
+        (old - 1) as usize
+    }
+
+    fn pin_count(&self) -> usize {
+        self.pin_count.load(Ordering::Acquire) as usize
+    }
+
+    fn try_reclaim(&self) -> bool {
+        !self.reclaiming.compare_and_swap(false, true, Ordering::AcqRel)
+    }
+
+    fn clear_reclaim(&self) {
+        self.reclaiming.store(false, Ordering::Relaxed);
+    }
 }
 
 /// Public interface
-impl <T> Pool<T> {
-    pub fn new(mem: &mut [u8]) -> Pool<T> {
-        let ptr: *mut u8 = mem.as_mut_ptr();
-        let header_size = mem::size_of::<SlotHeader>();
-        let slot_size = mem::size_of::<T>() + header_size;
+impl <T, H: SlotMeta> Pool<T, H> {
+    pub fn new(mem: &mut [u8]) -> Pool<T, H> {
+        let slot_align = H::header_align().max(mem::align_of::<T>());
+
+        // Advance the base to the first address satisfying slot_align, so
+        // the header (and hence every payload) lands on an aligned boundary.
+        let raw_addr = mem.as_mut_ptr() as usize;
+        let aligned_addr = round_up(raw_addr, slot_align);
+        let base_offset = aligned_addr - raw_addr;
+        let ptr: *mut u8 = unsafe { mem.as_mut_ptr().offset(base_offset as isize) };
+
+        let header_size = H::header_size();
+        // The payload must be able to hold a usize free-list link even
+        // when freed, regardless of how small T is.
+        let payload_size = mem::size_of::<T>().max(mem::size_of::<usize>());
+        let payload_offset = round_up(header_size, mem::align_of::<T>());
+        let slot_size = round_up(payload_offset + payload_size, slot_align);
+
+        let usable_len = mem.len().saturating_sub(base_offset);
+        let capacity = round_down(usable_len, slot_size) / slot_size;
+
+        // `mem` is only guaranteed to be valid, not zeroed -- `static_pool!`
+        // in particular hands this a genuinely uninitialized
+        // `MaybeUninit` buffer. Write a fresh header into every slot now
+        // rather than relying on whatever bytes happen to already be
+        // there being a valid `H` (e.g. a zero ref count).
+        for i in 0..capacity {
+            unsafe {
+                let header_ptr = ptr.offset((i * slot_size) as isize) as *mut H;
+                ptr::write(header_ptr, H::default());
+            }
+        }
+
+        let tag_bits = free_list_tag_bits(capacity);
+
         Pool {
             item_type: PhantomData,
+            header_type: PhantomData,
             buffer: ptr,
-            buffer_size: mem.len(),
+            buffer_size: usable_len,
             tail: AtomicUsize::new(0),
             slot_size: slot_size,
-            capacity: mem.len() / slot_size,
+            capacity: capacity,
             header_size: header_size,
-            free_list: LinkedList::new(),
+            payload_offset: payload_offset,
+            free_list_tag_bits: tag_bits,
+            free_head: AtomicUsize::new(pack_free_head(free_list_empty(tag_bits), 0, tag_bits)),
+            free_count: AtomicUsize::new(0),
+            #[cfg(feature = "std")]
+            waiters: Mutex::new(VecDeque::new()),
+            #[cfg(feature = "std")]
+            next_waiter_token: AtomicUsize::new(0),
         }
     }
 
@@ -170,8 +561,8 @@ impl <T> Pool<T> {
 
     /// Fast copy a slot's contents to a new slot and return
     /// a pointer to the new slot
-    pub fn alloc_with_contents_of(&mut self, other: &Arc<T>) -> Result<Arc<T>, &'static str> {
-        let index = try!(self.claim_free_index());
+    pub fn alloc_with_contents_of(&self, other: &Arc<T, H>) -> Result<Arc<T, H>, &'static str> {
+        let index = self.claim_free_index()?;
         unsafe {
             let from = self.raw_contents_for(other.index);
             let to = self.raw_contents_for(index);
@@ -182,77 +573,111 @@ impl <T> Pool<T> {
 
     /// Try to allocate a new item from the pool.
     /// A mutable reference to the item is returned on success
-    pub fn alloc(&mut self) -> Result<Arc<T>, &'static str> {
-        let index = try!(self.internal_alloc());
+    pub fn alloc(&self) -> Result<Arc<T, H>, &'static str> {
+        let index = self.internal_alloc()?;
         Ok(Arc::new(index, self))
     }
 
+    /// Like `alloc`, but instead of returning `Err("OOM")` when the pool
+    /// is full, the returned future parks the caller's waker and resolves
+    /// once a `release` on another thread frees a slot. This makes the
+    /// pool a natural backpressure primitive for bounded concurrent
+    /// workloads.
+    #[cfg(feature = "std")]
+    pub fn alloc_async(&self) -> AllocFuture<'_, T, H> {
+        AllocFuture { pool: self }
+    }
+
     // Increase the ref count for the cell at the given index
-    pub fn retain(&mut self, index: usize) {
-        let h = self.header_for(index);
-        loop {
-            let old = h.ref_count.load(Ordering::Relaxed);
-            let swap = h.ref_count
-            .compare_and_swap(old, old+1, Ordering::Relaxed);
-            if swap == old {
-                break
-            }
-        }
+    pub fn retain(&self, index: usize) {
+        self.header_for(index).retain();
     }
 
     // Decrease the ref count for the cell at the given index
-    pub fn release(&mut self, index: usize) {
-        let mut is_free = false;
-        { // Make the borrow checker happy
-            let h = self.header_for(index);
-            loop {
-                let old = h.ref_count.load(Ordering::Relaxed);
-                assert!(old > 0, "Release called on [{}] which has no refs!", index);
-
-                let swap = h.ref_count
-                .compare_and_swap(old, old-1, Ordering::Relaxed);
-                if swap == old {
-                    if old == 1 { // this was the last reference
-                        is_free = true;
-                    }
-                    break
-                }
-            }
+    pub fn release(&self, index: usize) {
+        if self.header_for(index).release() == 0 {
+            self.maybe_free(index);
         }
-        if is_free {
-            self.free_list.push_back(index);
+    }
+
+    /// Like indexing, but returns None if `generation` doesn't match the
+    /// slot's current generation (i.e. the slot has since been freed and
+    /// recycled for another object).
+    pub fn try_get(&self, index: usize, generation: usize) -> Option<&T> {
+        if self.header_for(index).generation() == generation {
+            Some(&self[index])
+        } else {
+            None
         }
     }
 
+    /// Borrow the slot at `index` without cloning/dropping an `Arc`,
+    /// returning a `Ref` guard that derefs to `T` for as long as it's
+    /// held. Returns `None` if `generation` doesn't match the slot's
+    /// current generation (i.e. the slot has since been freed and
+    /// recycled for another object), or if it has no live references at
+    /// the moment `get` is called. Like `try_get`, this takes a
+    /// generation so a stale index can never be mistaken for the object
+    /// it used to denote.
+    ///
+    /// While a `Ref` is outstanding, a `release` that drops the last
+    /// `Arc`'s ref count to zero does not return the slot to the free
+    /// list -- reclamation is deferred until the last `Ref` also drops,
+    /// so a caller reading through a `Ref` can never have its slot
+    /// recycled out from under it.
+    pub fn get(&self, index: usize, generation: usize) -> Option<Ref<'_, T, H>> {
+        let h = self.header_for(index);
+        // Check before pinning: an index that's never been allocated
+        // (past `tail`, or sitting untouched on the free stack) also
+        // reads a zero ref count, and pinning it would make maybe_free
+        // mistake it for a slot that just had its last Arc dropped.
+        if h.ref_count() == 0 || h.generation() != generation {
+            return None;
+        }
+        h.pin();
+        if h.ref_count() == 0 || h.generation() != generation {
+            // Raced with the last Arc's release() concurrently dropping
+            // the count to zero (or with the slot being recycled into a
+            // new generation entirely); release() will have seen our pin
+            // and deferred reclaiming the slot, so it's on us to finish
+            // that once we back our pin back out.
+            if h.unpin() == 0 {
+                self.maybe_free(index);
+            }
+            return None;
+        }
+        Some(Ref { pool: self, index: index })
+    }
+
     /// Returns the number of live items. O(1) running time.
     pub fn live_count(&self) -> usize {
-        self.tail.load(Ordering::Relaxed) - self.free_list.len()
+        self.tail.load(Ordering::Relaxed) - self.free_count.load(Ordering::Relaxed)
     }
 }
 
 
 /// Internal Functions
-impl <T> Pool<T> {
-    // Returns an item from the free list, or
+impl <T, H: SlotMeta> Pool<T, H> {
+    // Returns an item from the free stack, or
     // tries to allocate a new one from the buffer
-    fn claim_free_index(&mut self) -> Result<usize, &'static str> {
-        let index = match self.free_list.pop_front() {
+    fn claim_free_index(&self) -> Result<usize, &'static str> {
+        let index = match self.pop_free() {
             Some(i) => i,
-            None => try!(self.push_back_alloc()),
+            None => self.push_back_alloc()?,
         };
-        self.retain(index);
+        self.header_for(index).on_alloc();
         Ok(index)
     }
 
     // Internal alloc that does not create an Arc but still claims a slot
-    fn internal_alloc(&mut self) -> Result<usize, &'static str> {
-        let index = try!(self.claim_free_index());
+    fn internal_alloc(&self) -> Result<usize, &'static str> {
+        let index = self.claim_free_index()?;
         Ok(index)
     }
 
     // Pushes the end of the used space in the buffer back
     // returns the previous index
-    fn push_back_alloc(&mut self) -> Result<usize, &'static str> {
+    fn push_back_alloc(&self) -> Result<usize, &'static str> {
         loop {
             let old_tail = self.tail.load(Ordering::Relaxed);
             let swap = self.tail.compare_and_swap(old_tail, old_tail+1, Ordering::Relaxed);
@@ -266,7 +691,73 @@ impl <T> Pool<T> {
         }
     }
 
-    fn header_for<'a>(&'a mut self, i: usize) -> &'a mut SlotHeader {
+    // Pushes `index` onto the intrusive free stack. The "next" link is
+    // written into the freed slot's own payload bytes, so this never
+    // touches any side collection and needs only &self.
+    fn push_free(&self, index: usize) {
+        loop {
+            let old_head = self.free_head.load(Ordering::Acquire);
+            unsafe {
+                ptr::write(self.raw_contents_for(index) as *mut usize, old_head);
+            }
+            let (_, tag) = unpack_free_head(old_head, self.free_list_tag_bits);
+            let new_head = pack_free_head(index, tag.wrapping_add(1), self.free_list_tag_bits);
+            let swap = self.free_head.compare_and_swap(old_head, new_head, Ordering::AcqRel);
+            if swap == old_head {
+                self.free_count.fetch_add(1, Ordering::Relaxed);
+                break
+            }
+        }
+    }
+
+    // Pops the top of the intrusive free stack, or returns None if
+    // it's empty.
+    fn pop_free(&self) -> Option<usize> {
+        loop {
+            let old_head = self.free_head.load(Ordering::Acquire);
+            let (index, _) = unpack_free_head(old_head, self.free_list_tag_bits);
+            if index == free_list_empty(self.free_list_tag_bits) {
+                return None
+            }
+            let next_head = unsafe {
+                ptr::read(self.raw_contents_for(index) as *const usize)
+            };
+            let swap = self.free_head.compare_and_swap(old_head, next_head, Ordering::AcqRel);
+            if swap == old_head {
+                self.free_count.fetch_sub(1, Ordering::Relaxed);
+                return Some(index)
+            }
+        }
+    }
+
+    // Decrease the outstanding-Ref count for the cell at the given
+    // index; called by Ref::drop.
+    fn unpin(&self, index: usize) {
+        if self.header_for(index).unpin() == 0 {
+            self.maybe_free(index);
+        }
+    }
+
+    // Called after either the ref count or the pin count transitions to
+    // zero. If the other one is also zero, attempts to claim the slot
+    // for reclamation and, on success, bumps its generation and pushes
+    // it back onto the free stack. The claim in try_reclaim is what
+    // keeps this sound if release() and the last Ref::drop() both land
+    // here at once.
+    fn maybe_free(&self, index: usize) {
+        let h = self.header_for(index);
+        if h.ref_count() == 0 && h.pin_count() == 0 && h.try_reclaim() {
+            // Bump the generation before the slot goes back on the free
+            // stack, so no other thread can reclaim it with the old
+            // generation still observable.
+            h.bump_generation();
+            self.push_free(index);
+            #[cfg(feature = "std")]
+            self.wake_one_waiter();
+        }
+    }
+
+    fn header_for<'a>(&'a self, i: usize) -> &'a H {
         unsafe {
             let ptr = self.buffer.clone()
                 .offset((i * self.slot_size) as isize);
@@ -274,35 +765,202 @@ impl <T> Pool<T> {
         }
     }
 
-    fn raw_contents_for<'a>(&'a mut self, i: usize) -> *mut u8 {
+    fn raw_contents_for<'a>(&'a self, i: usize) -> *mut u8 {
         unsafe {
             self.buffer.clone()
                 .offset((i * self.slot_size) as isize)
-                .offset(self.header_size as isize)
+                .offset(self.payload_offset as isize)
+        }
+    }
+
+    // Wakes a single parked alloc_async() caller, if any, so it can retry
+    // claiming the slot that was just freed.
+    #[cfg(feature = "std")]
+    fn wake_one_waiter(&self) {
+        if let Some((_, w)) = self.waiters.lock().unwrap().pop_front() {
+            w.wake();
+        }
+    }
+
+    // Returns a token identifying this registration, so a caller whose
+    // post-registration recheck succeeds can cancel_waiter() it instead
+    // of leaving a registration behind that nothing will ever resolve --
+    // wake_one_waiter pops oldest-first, so an unresolved registration
+    // would eventually steal a wakeup from a still-pending waiter.
+    #[cfg(feature = "std")]
+    fn register_waiter(&self, waker: Waker) -> usize {
+        let token = self.next_waiter_token.fetch_add(1, Ordering::Relaxed);
+        self.waiters.lock().unwrap().push_back((token, waker));
+        token
+    }
+
+    // No-op if the token has already been popped by wake_one_waiter.
+    #[cfg(feature = "std")]
+    fn cancel_waiter(&self, token: usize) {
+        self.waiters.lock().unwrap().retain(|&(t, _)| t != token);
+    }
+}
+
+/// A scoped read guard returned by `Pool::get`, borrowing a slot without
+/// cloning its `Arc`. Implements `Deref` to `T`. Taking and dropping a
+/// `Ref` only ever changes the slot's outstanding-borrow count (one
+/// increment in `get`, one decrement on drop); the `Arc` ref count is
+/// only read, never written.
+pub struct Ref<'a, T, H: SlotMeta = DefaultHeader> {
+    pool: &'a Pool<T, H>,
+    index: usize,
+}
+
+impl <'a, T, H: SlotMeta> Deref for Ref<'a, T, H> {
+    type Target = T;
+
+    fn deref<'b>(&'b self) -> &'b T {
+        &self.pool[self.index]
+    }
+}
+
+impl <'a, T, H: SlotMeta> Drop for Ref<'a, T, H> {
+    fn drop(&mut self) {
+        self.pool.unpin(self.index);
+    }
+}
+
+/// Future returned by `Pool::alloc_async`. Resolves immediately if a slot
+/// is free; otherwise parks the caller's waker until `release` notifies it.
+#[cfg(feature = "std")]
+pub struct AllocFuture<'a, T: 'a, H: SlotMeta = DefaultHeader> {
+    pool: &'a Pool<T, H>,
+}
+
+#[cfg(feature = "std")]
+impl <'a, T, H: SlotMeta> Future for AllocFuture<'a, T, H> {
+    type Output = Arc<T, H>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Arc<T, H>> {
+        if let Ok(arc) = self.pool.alloc() {
+            return Poll::Ready(arc)
+        }
+        let token = self.pool.register_waiter(cx.waker().clone());
+        // Re-check after registering: a release on another thread could
+        // have freed a slot and already notified (or be about to notify)
+        // a waiter between our first attempt and the registration above.
+        // Without this, that wakeup would be lost.
+        match self.pool.alloc() {
+            Ok(arc) => {
+                // The slot we just grabbed means our registration above
+                // will never be resolved by a release -- take it back out
+                // so wake_one_waiter doesn't waste a future wakeup on it.
+                self.pool.cancel_waiter(token);
+                Poll::Ready(arc)
+            },
+            Err(_) => Poll::Pending,
         }
     }
 }
 
-impl <T> Index<usize> for Pool<T> {
+impl <T, H: SlotMeta> Index<usize> for Pool<T, H> {
     type Output = T;
 
     fn index<'a>(&'a self, i: usize) -> &'a T {
         unsafe {
             let ptr = self.buffer.clone()
                 .offset((i * self.slot_size) as isize)
-                .offset(self.header_size as isize);
+                .offset(self.payload_offset as isize);
             mem::transmute(ptr)
         }
     }
 }
 
-impl <T> IndexMut<usize> for Pool<T> {
+impl <T, H: SlotMeta> IndexMut<usize> for Pool<T, H> {
     fn index_mut<'a>(&'a mut self, i: usize) -> &'a mut T {
         unsafe {
             let ptr = self.buffer.clone()
                 .offset((i * self.slot_size) as isize)
-                .offset(self.header_size as isize);
+                .offset(self.payload_offset as isize);
             mem::transmute(ptr)
         }
     }
 }
+
+const STATIC_POOL_UNINIT: usize = 0;
+const STATIC_POOL_INITIALIZING: usize = 1;
+const STATIC_POOL_READY: usize = 2;
+
+/// Backing storage for a `Pool<T>` with `'static` lifetime and a
+/// compile-time-fixed byte budget, built to live inside a single `static`
+/// item (see the `static_pool!` macro). This is what makes a `Pool`
+/// usable with no allocator at all: the buffer and the `Pool` itself are
+/// both embedded directly in the static, and are lazily initialized the
+/// first time `get` is called.
+///
+/// `N` is a byte count, not an item count, matching `Pool::new`'s own
+/// byte-oriented `&mut [u8]` constructor.
+pub struct StaticPoolStorage<T, const N: usize> {
+    state: AtomicUsize,
+    buffer: UnsafeCell<MaybeUninit<[u8; N]>>,
+    pool: UnsafeCell<MaybeUninit<Pool<T>>>,
+}
+
+// SAFETY: access to `buffer` and `pool` is gated by `state`, which only
+// ever transitions UNINIT -> INITIALIZING -> READY once, under a
+// compare_and_swap that admits a single winner; every other caller spins
+// until READY and then only ever reads. Once READY, sharing the
+// `Pool<T>` inside across threads is exactly what `Pool`'s own `Sync`
+// impl (see its definition) requires `T: Send + Sync` for, so the same
+// bound is required here.
+unsafe impl <T: Send + Sync, const N: usize> Sync for StaticPoolStorage<T, N> {}
+
+impl <T, const N: usize> StaticPoolStorage<T, N> {
+    pub const fn new() -> StaticPoolStorage<T, N> {
+        StaticPoolStorage {
+            state: AtomicUsize::new(STATIC_POOL_UNINIT),
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+            pool: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the lazily-initialized pool, performing the one-time setup
+    /// on the first call. Safe to call concurrently from multiple
+    /// threads: only the caller that wins the UNINIT -> INITIALIZING race
+    /// runs `Pool::new`, everyone else spins until it's done.
+    pub fn get(&'static self) -> &'static Pool<T> {
+        loop {
+            match self.state.compare_and_swap(
+                STATIC_POOL_UNINIT, STATIC_POOL_INITIALIZING, Ordering::Acquire) {
+                STATIC_POOL_UNINIT => {
+                    unsafe {
+                        let buffer = &mut *(*self.buffer.get()).as_mut_ptr();
+                        let pool = Pool::new(&mut buffer[..]);
+                        ptr::write((*self.pool.get()).as_mut_ptr(), pool);
+                    }
+                    self.state.store(STATIC_POOL_READY, Ordering::Release);
+                    break
+                }
+                STATIC_POOL_READY => break,
+                _ /* STATIC_POOL_INITIALIZING */ => continue,
+            }
+        }
+        unsafe { &*(*self.pool.get()).as_ptr() }
+    }
+}
+
+/// Declares a function returning a `&'static Pool<$ty>` backed by a
+/// `$bytes`-byte static buffer, lazily initialized on first use. `$bytes`
+/// is a byte count, not an item count, so it composes directly with
+/// `Pool::new`'s own `&mut [u8]` constructor without needing to redo the
+/// header/alignment math at macro-expansion time.
+///
+/// ```ignore
+/// static_pool!(connections: Pool<Connection>, 4096);
+/// let conn = connections().alloc().unwrap();
+/// ```
+#[macro_export]
+macro_rules! static_pool {
+    ($name:ident : Pool<$ty:ty>, $bytes:expr) => {
+        fn $name() -> &'static $crate::Pool<$ty> {
+            static STORAGE: $crate::StaticPoolStorage<$ty, { $bytes }> =
+                $crate::StaticPoolStorage::new();
+            STORAGE.get()
+        }
+    };
+}
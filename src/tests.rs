@@ -1,11 +1,27 @@
 use super::*;
 use std::mem;
-use std::sync::atomic::{Ordering};
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll, Waker};
+use std::pin::pin;
+
+// A 64-byte aligned backing buffer for the pools under test. Plain
+// `[u8; N]` locals are only aligned as much as the surrounding stack
+// frame happens to need, which varies with optimization level and would
+// make the byte-offset assertions below flaky; this pins it down so the
+// tests are deterministic regardless of build settings.
+#[repr(align(64))]
+struct AlignedBuf<const N: usize>([u8; N]);
+
+impl <const N: usize> AlignedBuf<N> {
+    fn new() -> AlignedBuf<N> {
+        AlignedBuf([0; N])
+    }
+}
 
 #[test]
 fn release_frees() {
-       let mut buf: [u8; 100] = [0; 100];
-       let mut p = Pool::<u32>::new(&mut buf[..]);
+       let mut buf = AlignedBuf::<100>::new();
+       let mut p = Pool::<u32>::new(&mut buf.0[..]);
 
        // Use internal_alloc so that the Arc doesn't drop
        // the reference immediately
@@ -16,36 +32,70 @@ fn release_frees() {
 
        p.release(0);
        assert_eq!(1, p.live_count());
-       assert_eq!(1, p.free_list.len());
-       assert_eq!(0, *p.free_list.front().unwrap());
+       assert_eq!(1, p.free_count.load(Ordering::Relaxed));
+       assert_eq!(Some(0), p.pop_free());
+
+       // Put it back so the rest of the test sees the expected state
+       p.push_free(0);
 
        p.release(1);
        assert_eq!(0, p.live_count());
-       assert_eq!(2, p.free_list.len());
+       assert_eq!(2, p.free_count.load(Ordering::Relaxed));
+}
+
+#[test]
+fn free_list_tag_widens_for_small_pools() {
+    // A handful of slots leaves nearly the whole word for the ABA tag...
+    assert!(free_list_tag_bits(3) >= USIZE_BITS - 4);
+    // ...while a pool sized near usize::max_value() has almost none to spare.
+    assert_eq!(0, free_list_tag_bits(usize::max_value()));
+
+    let mut buf = AlignedBuf::<100>::new();
+    let p = Pool::<u32>::new(&mut buf.0[..]);
+    assert_eq!(free_list_tag_bits(p.capacity), p.free_list_tag_bits);
+}
+
+#[test]
+fn free_stack_is_lifo() {
+       let mut buf = AlignedBuf::<200>::new();
+       let mut p = Pool::<u32>::new(&mut buf.0[..]);
+       assert!(p.internal_alloc().is_ok());
+       assert!(p.internal_alloc().is_ok());
+       assert!(p.internal_alloc().is_ok());
+
+       p.release(0);
+       p.release(1);
+       p.release(2);
+
+       // Most recently freed comes back first
+       assert_eq!(Some(2), p.pop_free());
+       assert_eq!(Some(1), p.pop_free());
+       assert_eq!(Some(0), p.pop_free());
+       assert_eq!(None, p.pop_free());
 }
 
 #[test]
 fn alloc_after_free_recycles() {
-       let mut buf: [u8; 100] = [0; 100];
-       let mut p = Pool::<u32>::new(&mut buf[..]);
+       let mut buf = AlignedBuf::<100>::new();
+       let mut p = Pool::<u32>::new(&mut buf.0[..]);
        assert!(p.internal_alloc().is_ok());
        assert_eq!(1, p.live_count());
        assert_eq!(1, p.tail.load(Ordering::Relaxed));
 
        p.release(0);
        assert_eq!(0, p.live_count());
-       assert_eq!(1, p.free_list.len());
+       assert_eq!(1, p.free_count.load(Ordering::Relaxed));
 
        assert!(p.internal_alloc().is_ok());
        assert_eq!(1, p.tail.load(Ordering::Relaxed)); // Tail shouldn't move
        assert_eq!(1, p.live_count());
-       assert_eq!(0, p.free_list.len());
+       assert_eq!(0, p.free_count.load(Ordering::Relaxed));
 }
 
 #[test]
 fn arc_clone() {
-    let mut buf: [u8; 100] = [0; 100];
-    let mut p = Pool::<u32>::new(&mut buf[..]);
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
     {
         let mut int1:Arc<u32> = p.alloc().unwrap();
         assert_eq!(1, int1.ref_count());
@@ -58,17 +108,17 @@ fn arc_clone() {
         }
         // Now, the clone should have been dropped, but no memory reclaimed
         assert_eq!(1, p.header_for(0).ref_count.load(Ordering::Relaxed));
-        assert_eq!(0, p.free_list.len());
+        assert_eq!(0, p.free_count.load(Ordering::Relaxed));
     }
     // Now, int1 should have been dropped, and all memory reclaimed
     assert_eq!(0, p.header_for(0).ref_count.load(Ordering::Relaxed));
-    assert_eq!(1, p.free_list.len());
+    assert_eq!(1, p.free_count.load(Ordering::Relaxed));
 }
 
 #[test]
 fn arc_drop() {
-    let mut buf: [u8; 100] = [0; 100];
-    let mut p = Pool::<u32>::new(&mut buf[..]);
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
     {
         let mut int1:Arc<u32> = p.alloc().unwrap();
         assert_eq!(1, int1.ref_count());
@@ -80,67 +130,378 @@ fn arc_drop() {
         }
         // Now, int2 should have been dropped
         assert_eq!(0, p.header_for(1).ref_count.load(Ordering::Relaxed));
-        assert_eq!(1, p.free_list.len());
+        assert_eq!(1, p.free_count.load(Ordering::Relaxed));
     }
     // Now, int1 should have been dropped
     assert_eq!(0, p.header_for(0).ref_count.load(Ordering::Relaxed));
-    assert_eq!(2, p.free_list.len());
+    assert_eq!(2, p.free_count.load(Ordering::Relaxed));
 }
 
 #[test]
 fn construction() {
-    let mut buf: [u8; 100] = [0; 100];
-    let mut p = Pool::<u32>::new(&mut buf[..]);
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
 
     assert_eq!(100, p.buffer_size);
-    assert_eq!(mem::size_of::<usize>(), p.header_size);
+    // DefaultHeader: ref_count + generation + pin_count, each a usize,
+    // plus the reclaim latch bool (padded up to the struct's alignment).
+    assert_eq!(mem::size_of::<DefaultHeader>(), p.header_size);
 
-    let expected_size = mem::size_of::<usize>() + mem::size_of::<u32>();
+    // The payload is padded up to size_of::<usize>() so a freed slot can
+    // always hold the intrusive free-stack link, even for small T.
+    let expected_size = p.header_size + mem::size_of::<usize>().max(mem::size_of::<u32>());
     assert_eq!(expected_size, p.slot_size);
-    assert_eq!(100/expected_size, p.capacity); // expected_size should be 8+4=12
-    assert_eq!(8, p.capacity);
+    assert_eq!(100/expected_size, p.capacity); // expected_size should be 32+8=40
+    assert_eq!(2, p.capacity);
 }
 
 #[test]
 fn free_list_alloc_works() {
-    let mut buf: [u8; 100] = [0; 100];
-    let mut p = Pool::<u32>::new(&mut buf[..]);
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
     {
         let mut int1:Arc<u32> = p.alloc().unwrap();
-        *int1 = 42;
-        // Check payload
-        assert_eq!([42u8, 0u8, 0u8, 0u8][..], buf[8..12]);
+        *int1.get_mut().unwrap() = 42;
+        // Check payload (after the 32-byte DefaultHeader)
+        assert_eq!([42u8, 0u8, 0u8, 0u8][..], buf.0[32..36]);
         // Check ref_count
-        assert_eq!([1u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8][..], buf[0..8]);
+        assert_eq!([1u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8][..], buf.0[0..8]);
         assert_eq!(1, p.live_count());
     }
     // int1 is now out of scope, let's ensure the drop worked
-    assert_eq!([0u8; 8][..], buf[0..8]);
+    assert_eq!([0u8; 8][..], buf.0[0..8]);
+}
+
+#[test]
+fn new_initializes_headers_over_non_zeroed_memory() {
+    // Simulates backing storage that isn't guaranteed-zeroed (like
+    // static_pool!'s MaybeUninit buffer): without Pool::new explicitly
+    // writing a fresh H::default() into every slot, these garbage bytes
+    // would be read back as an already-huge ref count and panic on the
+    // first alloc().
+    #[repr(align(64))]
+    struct GarbageBuf([u8; 400]);
+    let mut buf = GarbageBuf([0xFFu8; 400]);
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
+
+    let mut item = p.alloc().unwrap();
+    *item.get_mut().unwrap() = 1;
+    assert_eq!(1, p.header_for(0).ref_count.load(Ordering::Relaxed));
+    assert_eq!(1, p.live_count());
 }
 
 #[test]
 fn check_oom_error() {
-    let mut buf: [u8; 1] = [0; 1];
-    let mut p = Pool::<u32>::new(&mut buf[..]);
+    let mut buf = AlignedBuf::<1>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
     assert_eq!(Err("OOM"), p.alloc());
 }
 
 #[test]
 fn multiple_allocations_work() {
-    let mut buf: [u8; 120] = [0; 120];
-    let mut p = Pool::<u32>::new(&mut buf[..]);
+    let mut buf = AlignedBuf::<400>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
     for i in 0..10 {
         let mut int1 = p.alloc().unwrap();
-        *int1 = i;
+        *int1.get_mut().unwrap() = i;
         unsafe { int1.retain() }; // Make sure this stays around long enough to read later
    }
    assert_eq!(10, p.live_count());
    let expected_ref_count = [1u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8];
    for i in 0..10 {
-       let start = 12*i;
+       let start = 40*i;
        // Check ref_count
-       assert_eq!(expected_ref_count[..], buf[start..start+8]);
+       assert_eq!(expected_ref_count[..], buf.0[start..start+8]);
        // Check payload
-       assert_eq!([i as u8, 0u8, 0u8, 0u8][..], buf[start+8..start+12]);
+       assert_eq!([i as u8, 0u8, 0u8, 0u8][..], buf.0[start+32..start+36]);
+    }
+}
+
+#[test]
+fn generation_detects_stale_index() {
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
+    assert!(p.internal_alloc().is_ok());
+    let gen0 = p.header_for(0).generation.load(Ordering::Relaxed);
+    assert_eq!(Some(&0u32), p.try_get(0, gen0));
+
+    p.release(0);
+    assert!(p.internal_alloc().is_ok()); // recycles slot 0
+    let gen1 = p.header_for(0).generation.load(Ordering::Relaxed);
+
+    assert_ne!(gen0, gen1);
+    assert_eq!(None, p.try_get(0, gen0)); // stale generation
+    assert!(p.try_get(0, gen1).is_some());
+}
+
+#[test]
+fn arc_try_deref_detects_recycling() {
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
+    let mut int1: Arc<u32> = p.alloc().unwrap();
+    *int1.get_mut().unwrap() = 7;
+    assert_eq!(Some(&7), int1.try_deref());
+
+    unsafe { int1.release(); } // drop the last ref without dropping the Arc itself
+    assert!(p.alloc().is_ok()); // recycles the same slot with a new generation
+    assert_eq!(None, int1.try_deref());
+
+    mem::forget(int1); // the slot's ref count is already at zero
+}
+
+#[test]
+fn arc_get_mut_requires_exclusive_access() {
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
+    let mut int1: Arc<u32> = p.alloc().unwrap();
+    assert!(int1.get_mut().is_some());
+
+    let int2 = int1.clone();
+    // A second live Arc means mutation would alias its reads.
+    assert!(int1.get_mut().is_none());
+
+    drop(int2);
+    assert!(int1.get_mut().is_some());
+}
+
+#[test]
+fn arc_get_mut_is_blocked_by_an_outstanding_ref() {
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
+    let mut int1: Arc<u32> = p.alloc().unwrap();
+    let gen0 = p.header_for(0).generation.load(Ordering::Relaxed);
+
+    let r = p.get(0, gen0).unwrap();
+    // A Ref guard is a second, cheaper read handle to the same slot --
+    // get_mut must treat it the same as a second Arc clone.
+    assert!(int1.get_mut().is_none());
+
+    drop(r);
+    assert!(int1.get_mut().is_some());
+}
+
+#[test]
+fn ref_borrows_without_touching_arc_refcount() {
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
+    let mut int1: Arc<u32> = p.alloc().unwrap();
+    *int1.get_mut().unwrap() = 42;
+
+    {
+        let gen0 = p.header_for(0).generation.load(Ordering::Relaxed);
+        let r = p.get(0, gen0).unwrap();
+        assert_eq!(42, *r);
+        // Borrowing via Ref never touches the Arc ref count.
+        assert_eq!(1, p.header_for(0).ref_count.load(Ordering::Relaxed));
     }
+    assert_eq!(1, p.header_for(0).ref_count.load(Ordering::Relaxed));
+}
+
+#[test]
+fn ref_defers_reclaim_until_dropped() {
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
+    assert!(p.internal_alloc().is_ok());
+
+    let gen0 = p.header_for(0).generation.load(Ordering::Relaxed);
+    let r = p.get(0, gen0).unwrap();
+    p.release(0); // drops the only Arc ref; a Ref is still outstanding
+    assert_eq!(0, p.free_count.load(Ordering::Relaxed));
+    assert_eq!(0u32, *r); // the slot is still readable through r
+
+    drop(r); // last guard gone: now it's actually freed
+    assert_eq!(1, p.free_count.load(Ordering::Relaxed));
+}
+
+#[test]
+fn get_returns_none_for_an_empty_slot() {
+    let mut buf = AlignedBuf::<100>::new();
+    let p = Pool::<u32>::new(&mut buf.0[..]);
+    assert!(p.get(0, 0).is_none());
+    // A speculative get() on a never-allocated slot must not push it onto
+    // the free stack -- it was never claimed from `tail` in the first place.
+    assert_eq!(0, p.free_count.load(Ordering::Relaxed));
+    assert_eq!(0, p.live_count());
+}
+
+#[test]
+fn get_returns_none_for_a_stale_generation() {
+    let mut buf = AlignedBuf::<100>::new();
+    let mut p = Pool::<u32>::new(&mut buf.0[..]);
+    assert!(p.internal_alloc().is_ok());
+    let gen0 = p.header_for(0).generation.load(Ordering::Relaxed);
+
+    p.release(0);
+    assert!(p.internal_alloc().is_ok()); // recycles slot 0
+    let gen1 = p.header_for(0).generation.load(Ordering::Relaxed);
+
+    assert_ne!(gen0, gen1);
+    assert!(p.get(0, gen0).is_none()); // stale generation
+    assert!(p.get(0, gen1).is_some());
+}
+
+#[test]
+fn alloc_async_resolves_immediately_when_a_slot_is_free() {
+    let mut buf = AlignedBuf::<100>::new();
+    let p = Pool::<u32>::new(&mut buf.0[..]);
+
+    let waker = Waker::noop();
+    let mut cx = Context::from_waker(waker);
+    let mut fut = pin!(p.alloc_async());
+    match fut.as_mut().poll(&mut cx) {
+        Poll::Ready(_) => {},
+        Poll::Pending => panic!("expected an immediate resolution on a pool with free slots"),
+    }
+}
+
+#[test]
+fn alloc_async_parks_then_resolves_after_a_release() {
+    let mut buf = AlignedBuf::<40>::new(); // room for exactly one u32 slot
+    let p = Pool::<u32>::new(&mut buf.0[..]);
+    let first = p.alloc().unwrap();
+
+    let waker = Waker::noop();
+    let mut cx = Context::from_waker(waker);
+    let mut fut = pin!(p.alloc_async());
+    assert_eq!(Poll::Pending, fut.as_mut().poll(&mut cx));
+
+    drop(first); // frees the only slot and wakes the parked future
+    match fut.as_mut().poll(&mut cx) {
+        Poll::Ready(_) => {},
+        Poll::Pending => panic!("expected the freed slot to resolve the future"),
+    }
+    // The woken registration was popped off by wake_one_waiter (see
+    // drop(first) above); resolving shouldn't have left anything behind.
+    assert_eq!(0, p.waiters.lock().unwrap().len());
+}
+
+#[test]
+fn cancel_waiter_removes_its_own_registration_without_touching_others() {
+    let mut buf = AlignedBuf::<40>::new();
+    let p = Pool::<u32>::new(&mut buf.0[..]);
+
+    // Mirrors what AllocFuture::poll does when its post-register recheck
+    // finds a slot after all: it takes its own registration back out
+    // instead of leaving it for wake_one_waiter to pop and waste a
+    // wakeup on later.
+    let waker = Waker::noop();
+    let token = p.register_waiter(waker.clone());
+    assert_eq!(1, p.waiters.lock().unwrap().len());
+    p.cancel_waiter(token);
+    assert_eq!(0, p.waiters.lock().unwrap().len());
+
+    // A still-pending registration made after ours is unaffected by the
+    // (already-applied) cancellation.
+    let other_token = p.register_waiter(waker.clone());
+    assert_ne!(token, other_token);
+    assert_eq!(1, p.waiters.lock().unwrap().len());
+    p.cancel_waiter(token); // cancelling an already-cancelled token is a no-op
+    assert_eq!(1, p.waiters.lock().unwrap().len());
+}
+
+#[test]
+fn payload_pointers_are_aligned_for_overaligned_types() {
+    #[repr(align(16))]
+    #[derive(Default)]
+    struct Align16(u32);
+
+    #[repr(align(32))]
+    #[derive(Default)]
+    struct Align32(u8);
+
+    let mut buf16 = AlignedBuf::<256>::new();
+    let p16 = Pool::<Align16>::new(&mut buf16.0[..]);
+    for _ in 0..p16.capacity {
+        let item = p16.alloc().unwrap();
+        let addr = &*item as *const Align16 as usize;
+        assert_eq!(0, addr % mem::align_of::<Align16>());
+    }
+
+    let mut buf32 = AlignedBuf::<256>::new();
+    let p32 = Pool::<Align32>::new(&mut buf32.0[..]);
+    for _ in 0..p32.capacity {
+        let item = p32.alloc().unwrap();
+        let addr = &*item as *const Align32 as usize;
+        assert_eq!(0, addr % mem::align_of::<Align32>());
+    }
+}
+
+static_pool!(test_static_pool: Pool<u32>, 400);
+
+#[test]
+fn static_pool_macro_lazily_inits_a_shared_static_pool() {
+    let p = test_static_pool();
+    assert_eq!(10, p.capacity);
+
+    let mut item = p.alloc().unwrap();
+    *item.get_mut().unwrap() = 42;
+    assert_eq!(1, p.live_count());
+
+    // A second call returns the same, already-initialized pool.
+    assert!(std::ptr::eq(p, test_static_pool()));
+}
+
+#[test]
+fn static_pool_storage_get_is_idempotent_across_threads() {
+    use std::thread;
+
+    static STORAGE: StaticPoolStorage<u32, 240> = StaticPoolStorage::new();
+    let storage: &'static StaticPoolStorage<u32, 240> = &STORAGE;
+
+    let pointers: Vec<usize> = (0..8)
+        .map(|_| thread::spawn(move || storage.get() as *const Pool<u32> as usize))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .collect();
+
+    let first = pointers[0];
+    assert!(pointers.iter().all(|&p| p == first));
+}
+
+#[test]
+fn pool_allocs_and_releases_concurrently_from_multiple_threads() {
+    let mut buf = AlignedBuf::<4096>::new();
+    let p: Pool<u32> = Pool::new(&mut buf.0[..]);
+    let p = &p;
+
+    std::thread::scope(|s| {
+        for t in 0..4 {
+            s.spawn(move || {
+                for i in 0..100 {
+                    let mut item = p.alloc().unwrap();
+                    *item.get_mut().unwrap() = t * 100 + i;
+                    assert_eq!(t * 100 + i, *item);
+                    // Dropped here, releasing back to the pool from
+                    // whichever thread got scheduled -- exercises the
+                    // Treiber free stack and generation bump under real
+                    // cross-thread contention, not just single-threaded
+                    // interleavings.
+                }
+            });
+        }
+    });
+
+    assert_eq!(0, p.live_count());
+}
+
+#[test]
+fn arc_moves_to_another_thread_and_releases_there() {
+    let mut buf = AlignedBuf::<100>::new();
+    let p: Pool<u32> = Pool::new(&mut buf.0[..]);
+    let p = &p;
+
+    let mut item = p.alloc().unwrap();
+    *item.get_mut().unwrap() = 7;
+
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            // item (and the ref count it owns) moved here from the
+            // thread that allocated it.
+            assert_eq!(7, *item);
+        }).join().unwrap();
+    });
+
+    // item's Drop ran on the spawned thread; the slot should be free.
+    assert_eq!(0, p.live_count());
 }